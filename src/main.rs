@@ -1,12 +1,33 @@
+//! This tree has never carried its own `Cargo.toml`/lockfile — it's built and
+//! tested against a manifest that lives outside this repo, which declares
+//! `mysql_async` (with the `binlog` feature and a `rustls-tls`/`native-tls-tls`
+//! feature for `opts::ssl_opts`), `futures-util`, `tokio` (`rt-multi-thread`,
+//! `macros`, `time`), `log`, `env_logger`, `dotenvy`, and, as of the manifest
+//! module, `serde` (`derive`) and `toml`. Wiring an in-repo manifest is a
+//! separate piece of work; adding one here is out of scope for this bundle.
+
+mod binlog;
+mod manifest;
+mod opts;
+
+use futures_util::StreamExt;
 use log::{debug, error, info, warn};
+use manifest::Manifest;
 use mysql_async::prelude::*;
 use mysql_async::{Conn, Pool, Result, Row, Value};
 use std::env;
 use std::iter;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::task::JoinSet;
 
-const MAX_CONCURRENT: usize = 15;
+const DEFAULT_BATCH_SIZE: usize = 1000;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RECONNECT_DELAY_SECS: u64 = 5;
+
+const ER_LOCK_DEADLOCK: u16 = 1213;
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -14,14 +35,17 @@ async fn main() -> Result<()> {
     setup_logger();
 
     let ignore_tables = ignore_tables();
-    let pool_source = mysql_async::Pool::new(source_database_url().as_str());
-    let pool_target = mysql_async::Pool::new(target_database_url().as_str());
+    let manifest = Arc::new(Manifest::load());
+    let pool_source = Pool::new(opts::source_opts());
+    let pool_target = Pool::new(opts::target_opts());
 
     let mut conn_source = pool_source.get_conn().await?;
     let source_tables = get_tables(&mut conn_source).await?;
 
     let mut task_set = JoinSet::new();
     let mut processed_tables = 0;
+    let mut failed_tables: Vec<String> = Vec::new();
+    let mut mismatched_tables: Vec<String> = Vec::new();
     let total_tables = source_tables
         .iter()
         .filter(|t| !ignore_tables.contains(t))
@@ -33,37 +57,125 @@ async fn main() -> Result<()> {
             continue;
         }
 
-        while task_set.len() >= MAX_CONCURRENT {
-            let result = task_set.join_next().await.unwrap().unwrap();
+        while task_set.len() >= opts::pool_max() {
+            let (table, result) = task_set.join_next().await.unwrap().unwrap();
 
-            if let Err(e) = result {
-                error!("{} ", e);
+            match result {
+                Ok(true) => {}
+                Ok(false) => mismatched_tables.push(table),
+                Err(_) => failed_tables.push(table),
             }
             upgrade_progress(&mut processed_tables, total_tables);
         }
-        task_set.spawn(clone_table(
+        let table = table.to_string();
+        task_set.spawn(clone_table_with_retry(
             pool_source.clone(),
             pool_target.clone(),
-            table.to_string(),
+            table,
+            manifest.clone(),
         ));
     }
 
     while let Some(result) = task_set.join_next().await {
-        if let Err(e) = result.unwrap() {
-            error!("{} ", e);
+        let (table, result) = result.unwrap();
+
+        match result {
+            Ok(true) => {}
+            Ok(false) => mismatched_tables.push(table),
+            Err(_) => failed_tables.push(table),
         }
         upgrade_progress(&mut processed_tables, total_tables);
     }
 
     drop(conn_source);
 
+    if failed_tables.is_empty() {
+        info!("Cloned {} table(s) successfully.", total_tables);
+    } else {
+        error!(
+            "Failed to clone {} of {} table(s) after exhausting retries: {}",
+            failed_tables.len(),
+            total_tables,
+            failed_tables.join(", ")
+        );
+    }
+
+    if !mismatched_tables.is_empty() {
+        error!(
+            "Verification found {} table(s) with mismatched data: {}",
+            mismatched_tables.len(),
+            mismatched_tables.join(", ")
+        );
+    }
+
+    if sync_mode() == "binlog" {
+        info!("SYNC_MODE=binlog: starting incremental binlog sync");
+        return binlog::run(pool_source, pool_target, ignore_tables, manifest).await;
+    }
+
     pool_source.disconnect().await?;
     pool_target.disconnect().await?;
 
     Ok(())
 }
 
-async fn clone_table(pool_source: Pool, pool_target: Pool, table: String) -> Result<()> {
+/// Runs [`clone_table`] for `table`, retrying transient errors up to `MAX_RETRIES` times.
+async fn clone_table_with_retry(
+    pool_source: Pool,
+    pool_target: Pool,
+    table: String,
+    manifest: Arc<Manifest>,
+) -> (String, Result<bool>) {
+    let max_retries = max_retries();
+    let reconnect_delay = Duration::from_secs(reconnect_delay_secs());
+
+    let mut attempt = 0;
+    loop {
+        match clone_table(
+            pool_source.clone(),
+            pool_target.clone(),
+            table.clone(),
+            manifest.clone(),
+        )
+        .await
+        {
+            Ok(verified) => return (table, Ok(verified)),
+            Err(e) if attempt < max_retries && is_retryable(&e) => {
+                attempt += 1;
+                warn!(
+                    "Retrying table `{}` after transient error ({}/{}): {}",
+                    table, attempt, max_retries, e
+                );
+                tokio::time::sleep(reconnect_delay).await;
+            }
+            Err(e) => {
+                error!("Giving up on table `{}` after {} attempt(s): {}", table, attempt + 1, e);
+                return (table, Err(e));
+            }
+        }
+    }
+}
+
+/// Whether `err` is transient and worth retrying. Other `Driver` errors (bad
+/// auth plugin negotiation, malformed packets, ...) are config/protocol
+/// problems a retry won't fix.
+fn is_retryable(err: &mysql_async::Error) -> bool {
+    match err {
+        mysql_async::Error::Io(_) => true,
+        mysql_async::Error::Driver(mysql_async::DriverError::ConnectionClosed) => true,
+        mysql_async::Error::Server(e) => {
+            e.code == ER_LOCK_DEADLOCK || e.code == ER_LOCK_WAIT_TIMEOUT
+        }
+        _ => false,
+    }
+}
+
+async fn clone_table(
+    pool_source: Pool,
+    pool_target: Pool,
+    table: String,
+    manifest: Arc<Manifest>,
+) -> Result<bool> {
     let mut conn_source = pool_source.get_conn().await?;
     let mut conn_target = pool_target.get_conn().await?;
 
@@ -77,54 +189,162 @@ async fn clone_table(pool_source: Pool, pool_target: Pool, table: String) -> Res
         .query_drop(get_table_structure(&mut conn_source, &table).await?)
         .await?;
 
-    let rows = conn_source
-        .query::<Row, _>(format!("SELECT * FROM `{}`", table))
+    let table_config = manifest.table_config(&table);
+    if table_config.structure_only {
+        debug!("Skipping data copy for table `{}` (structure-only)", table);
+        return Ok(true);
+    }
+
+    debug!("Streaming rows from table `{}`", table);
+
+    let select_columns = select_columns(&mut conn_source, &table, &table_config).await?;
+    let where_clause = table_config
+        .where_clause
+        .as_deref()
+        .map(|w| format!(" WHERE {}", w))
+        .unwrap_or_default();
+
+    let batch_size = batch_size();
+    let mut column_names: Option<Vec<String>> = None;
+    let mut batch: Vec<Option<Value>> = Vec::with_capacity(batch_size);
+    let mut batch_rows = 0usize;
+    let mut row_count: u64 = 0;
+    let mut stream = conn_source
+        .query_stream::<Row, _>(format!(
+            "SELECT {} FROM `{}`{}",
+            select_columns, table, where_clause
+        ))
         .await?;
 
-    if rows.is_empty() {
-        return Ok(());
+    while let Some(row) = stream.next().await.transpose()? {
+        let column_names = column_names.get_or_insert_with(|| {
+            row.columns()
+                .iter()
+                .map(|col| format!("`{}`", col.name_str()))
+                .collect()
+        });
+
+        for col in row.columns().iter() {
+            batch.push(row.get::<Value, _>(col.name_str().as_ref()));
+        }
+        batch_rows += 1;
+        row_count += 1;
+
+        if batch_rows == batch_size {
+            flush_batch(&mut conn_target, &table, column_names, &mut batch, batch_rows).await?;
+            batch_rows = 0;
+        }
     }
+    drop(stream);
 
-    if rows.len() > 1_000_000 {
-        warn!(
-            "Table `{}` has more than 1 million rows, consider using IGNORE_TABLES to ignore this table.",
-            table
-        )
+    if batch_rows > 0 {
+        let column_names = column_names.as_ref().unwrap();
+        flush_batch(&mut conn_target, &table, column_names, &mut batch, batch_rows).await?;
     }
 
-    debug!("Inserting into table `{}` with {} rows.", table, rows.len());
+    debug!("Inserted into table `{}` with {} rows.", table, row_count);
 
-    for row in rows.iter() {
-        let column_names = row
-            .columns()
-            .iter()
-            .map(|col| format!("`{}`", col.name_str()))
-            .collect::<Vec<_>>();
-        let column_values = row
-            .columns()
-            .iter()
-            .map(|col| row.get::<Value, _>(col.name_str().as_ref()))
-            .collect::<Vec<_>>();
+    let verified = if verify_enabled() {
+        verify_table(&mut conn_source, &mut conn_target, &table, &table_config).await?
+    } else {
+        true
+    };
 
-        let insert_sql = format!(
-            "INSERT INTO `{}` ({}) VALUES ({})",
-            table,
-            column_names.join(", "),
-            iter::repeat("?")
-                .take(row.len())
-                .collect::<Vec<_>>()
-                .join(", ")
+    drop(conn_source);
+    drop(conn_target);
+
+    Ok(verified)
+}
+
+/// Compares source vs. target row counts, plus a `CHECKSUM TABLE` when cloned unfiltered.
+async fn verify_table(
+    conn_source: &mut Conn,
+    conn_target: &mut Conn,
+    table: &str,
+    table_config: &manifest::TableConfig,
+) -> Result<bool> {
+    let where_clause = table_config
+        .where_clause
+        .as_deref()
+        .map(|w| format!(" WHERE {}", w))
+        .unwrap_or_default();
+
+    let source_count: u64 = conn_source
+        .query_first(format!("SELECT COUNT(*) FROM `{}`{}", table, where_clause))
+        .await?
+        .unwrap_or(0);
+    let target_count: u64 = conn_target
+        .query_first(format!("SELECT COUNT(*) FROM `{}`", table))
+        .await?
+        .unwrap_or(0);
+
+    if source_count != target_count {
+        error!(
+            "Row count mismatch for table `{}`: source has {}, target has {}",
+            table, source_count, target_count
         );
+        return Ok(false);
+    }
+
+    let cloned_unfiltered = table_config.where_clause.is_none()
+        && table_config.include_columns.is_none()
+        && table_config.exclude_columns.is_none();
+
+    if cloned_unfiltered {
+        let source_checksum = table_checksum(conn_source, table).await?;
+        let target_checksum = table_checksum(conn_target, table).await?;
 
-        conn_target.exec_drop(insert_sql, column_values).await?;
+        if source_checksum != target_checksum {
+            error!(
+                "Checksum mismatch for table `{}`: source {:?}, target {:?}",
+                table, source_checksum, target_checksum
+            );
+            return Ok(false);
+        }
     }
 
-    debug!("Inserted into table `{}` with {} rows.", table, rows.len());
+    debug!("Verified table `{}`: {} row(s) match.", table, source_count);
 
-    drop(conn_source);
-    drop(conn_target);
+    Ok(true)
+}
 
-    Ok(())
+async fn table_checksum(conn: &mut Conn, table: &str) -> Result<Option<u64>> {
+    let row = conn
+        .query_first::<Row, _>(format!("CHECKSUM TABLE `{}`", table))
+        .await?;
+
+    Ok(row.and_then(|row| row.get(1)))
+}
+
+/// Flushes `batch` as a single multi-row `INSERT`.
+async fn flush_batch(
+    conn_target: &mut Conn,
+    table: &str,
+    column_names: &[String],
+    batch: &mut Vec<Option<Value>>,
+    batch_rows: usize,
+) -> Result<()> {
+    let insert_sql = build_insert_sql(table, column_names, batch_rows);
+
+    conn_target.exec_drop(insert_sql, std::mem::take(batch)).await
+}
+
+fn build_insert_sql(table: &str, column_names: &[String], batch_rows: usize) -> String {
+    let row_placeholders = format!(
+        "({})",
+        iter::repeat_n("?", column_names.len())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    format!(
+        "INSERT INTO `{}` ({}) VALUES {}",
+        table,
+        column_names.join(", "),
+        iter::repeat_n(row_placeholders.as_str(), batch_rows)
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
 }
 
 async fn get_tables(conn: &mut Conn) -> Result<Vec<String>> {
@@ -136,6 +356,42 @@ async fn get_tables(conn: &mut Conn) -> Result<Vec<String>> {
         .collect())
 }
 
+/// Resolves the column list to `SELECT` for `table` per its manifest `TableConfig`.
+async fn select_columns(
+    conn: &mut Conn,
+    table: &str,
+    table_config: &manifest::TableConfig,
+) -> Result<String> {
+    if let Some(include_columns) = &table_config.include_columns {
+        return Ok(quote_columns(include_columns));
+    }
+
+    let Some(exclude_columns) = &table_config.exclude_columns else {
+        return Ok("*".to_string());
+    };
+
+    let all_columns: Vec<String> = conn
+        .query_map(format!("SHOW COLUMNS FROM `{}`", table), |row: Row| {
+            row.get::<String, _>(0).unwrap()
+        })
+        .await?;
+
+    Ok(quote_columns(
+        &all_columns
+            .into_iter()
+            .filter(|c| !exclude_columns.contains(c))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn quote_columns(columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|c| format!("`{}`", c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 async fn get_table_structure(conn: &mut Conn, table: &str) -> Result<String> {
     Ok(conn
         .query_first::<Row, _>(format!("SHOW CREATE TABLE `{}`", table))
@@ -174,28 +430,95 @@ fn ignore_tables() -> Vec<String> {
         .collect()
 }
 
-fn get_env(key: &str) -> String {
-    env::var(key).expect(&format!("{} is not set in .env", key))
+fn batch_size() -> usize {
+    env::var("BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_BATCH_SIZE)
 }
 
-fn source_database_url() -> String {
-    format!(
-        "mysql://{}:{}@{}:{}/{}",
-        get_env("SOURCE_DB_USERNAME"),
-        get_env("SOURCE_DB_PASSWORD"),
-        get_env("SOURCE_DB_HOST"),
-        get_env("SOURCE_DB_PORT"),
-        get_env("SOURCE_DB_DATABASE"),
-    )
+fn max_retries() -> u32 {
+    env::var("MAX_RETRIES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_RETRIES)
 }
 
-fn target_database_url() -> String {
-    format!(
-        "mysql://{}:{}@{}:{}/{}",
-        get_env("TARGET_DB_USERNAME"),
-        get_env("TARGET_DB_PASSWORD"),
-        get_env("TARGET_DB_HOST"),
-        get_env("TARGET_DB_PORT"),
-        get_env("TARGET_DB_DATABASE"),
-    )
+fn reconnect_delay_secs() -> u64 {
+    env::var("RECONNECT_DELAY_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RECONNECT_DELAY_SECS)
+}
+
+fn sync_mode() -> String {
+    env::var("SYNC_MODE").unwrap_or("once".to_string())
+}
+
+fn verify_enabled() -> bool {
+    env::var("VERIFY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mysql_async::{DriverError, IoError, ServerError};
+
+    #[test]
+    fn retries_io_errors() {
+        let err = mysql_async::Error::Io(IoError::Io(std::io::Error::other("reset")));
+        assert!(is_retryable(&err));
+    }
+
+    #[test]
+    fn retries_closed_connections_but_not_other_driver_errors() {
+        assert!(is_retryable(&mysql_async::Error::Driver(
+            DriverError::ConnectionClosed
+        )));
+        assert!(!is_retryable(&mysql_async::Error::Driver(
+            DriverError::PacketTooLarge
+        )));
+    }
+
+    #[test]
+    fn retries_deadlocks_and_lock_wait_timeouts_but_not_other_server_errors() {
+        let deadlock = ServerError {
+            code: ER_LOCK_DEADLOCK,
+            message: "deadlock".into(),
+            state: "40001".into(),
+        };
+        let wait_timeout = ServerError {
+            code: ER_LOCK_WAIT_TIMEOUT,
+            message: "lock wait timeout".into(),
+            state: "HY000".into(),
+        };
+        let other = ServerError {
+            code: 1062,
+            message: "duplicate entry".into(),
+            state: "23000".into(),
+        };
+
+        assert!(is_retryable(&mysql_async::Error::Server(deadlock)));
+        assert!(is_retryable(&mysql_async::Error::Server(wait_timeout)));
+        assert!(!is_retryable(&mysql_async::Error::Server(other)));
+    }
+
+    #[test]
+    fn quotes_each_column() {
+        let columns = vec!["id".to_string(), "created_at".to_string()];
+        assert_eq!(quote_columns(&columns), "`id`, `created_at`");
+    }
+
+    #[test]
+    fn builds_multi_row_insert_sql() {
+        let columns = vec!["id".to_string(), "name".to_string()];
+        assert_eq!(
+            build_insert_sql("users", &columns, 2),
+            "INSERT INTO `users` (id, name) VALUES (?, ?), (?, ?)"
+        );
+    }
 }
+
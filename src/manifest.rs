@@ -0,0 +1,95 @@
+//! Optional TOML manifest (`CONFIG_FILE`) for per-table `WHERE` predicates,
+//! column include/exclude lists, and structure-only tables.
+//!
+//! ```toml
+//! [tables.events]
+//! where = "created_at > NOW() - INTERVAL 30 DAY"
+//! exclude_columns = ["session_token"]
+//!
+//! [tables.audit_log]
+//! structure_only = true
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    tables: HashMap<String, TableConfig>,
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct TableConfig {
+    #[serde(rename = "where", default)]
+    pub where_clause: Option<String>,
+    #[serde(default)]
+    pub include_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub exclude_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub structure_only: bool,
+}
+
+impl Manifest {
+    /// Loads the manifest pointed to by `CONFIG_FILE`, or the default (empty) one.
+    pub fn load() -> Manifest {
+        let Ok(path) = env::var("CONFIG_FILE") else {
+            return Manifest::default();
+        };
+
+        let contents = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Failed to read config file `{}`: {}", path, e));
+
+        toml::from_str(&contents)
+            .unwrap_or_else(|e| panic!("Failed to parse config file `{}`: {}", path, e))
+    }
+
+    pub fn table_config(&self, table: &str) -> TableConfig {
+        self.tables.get(table).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_per_table_config() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+            [tables.events]
+            where = "created_at > NOW() - INTERVAL 30 DAY"
+            exclude_columns = ["session_token"]
+
+            [tables.audit_log]
+            structure_only = true
+            "#,
+        )
+        .unwrap();
+
+        let events = manifest.table_config("events");
+        assert_eq!(
+            events.where_clause.as_deref(),
+            Some("created_at > NOW() - INTERVAL 30 DAY")
+        );
+        assert_eq!(events.exclude_columns, Some(vec!["session_token".to_string()]));
+        assert!(!events.structure_only);
+
+        let audit_log = manifest.table_config("audit_log");
+        assert!(audit_log.structure_only);
+    }
+
+    #[test]
+    fn defaults_unconfigured_tables() {
+        let manifest = Manifest::default();
+        let config = manifest.table_config("unconfigured");
+
+        assert_eq!(config.where_clause, None);
+        assert_eq!(config.include_columns, None);
+        assert_eq!(config.exclude_columns, None);
+        assert!(!config.structure_only);
+    }
+}